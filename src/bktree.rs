@@ -0,0 +1,250 @@
+/**
+ * BK-tree indexing of file sketches for near-duplicate clustering.
+ *
+ * Comparing every pair of files in a directory is O(n^2), which doesn't
+ * scale past a few thousand files. A BK-tree indexes files by an integer
+ * distance (`d = 100 - similarity_percent`) so that, thanks to the
+ * triangle inequality, a radius query only has to visit the handful of
+ * children whose distance to their parent could possibly fall within
+ * the query radius.
+ */
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sketch::{Sketch, DEFAULT_SKETCH_SIZE};
+use diffcore::{HashType, MAX_SCORE};
+use cache::Cache;
+
+/// Integer distance between two sketches, in the range `0..=100`.
+///
+/// `0` means identical, `100` means completely dissimilar; this is just
+/// `100 - similarity_percent`, which is what makes the triangle
+/// inequality (and therefore the BK-tree) work.
+fn distance(a: &Sketch, b: &Sketch) -> u32 {
+    let percent = a.estimate_similarity(b) * 100.0 / MAX_SCORE;
+    100 - (percent.round() as u32)
+}
+
+struct Node {
+    idx: usize,
+    sketch: Sketch,
+    children: HashMap<u32, Box<Node>>,
+}
+
+/// A BK-tree of file sketches, keyed by the distance metric above.
+#[derive(Default)]
+pub struct BKTree {
+    root: Option<Box<Node>>,
+}
+
+impl BKTree {
+    pub fn new() -> Self {
+        BKTree { root: None }
+    }
+
+    /// Insert a file (identified by `idx`, an index into the caller's
+    /// path list) with its precomputed sketch.
+    pub fn insert(&mut self, idx: usize, sketch: Sketch) {
+        match self.root {
+            None => {
+                self.root = Some(Box::new(Node {
+                    idx: idx,
+                    sketch: sketch,
+                    children: HashMap::new(),
+                }));
+            }
+            Some(ref mut root) => root.insert(idx, sketch),
+        }
+    }
+
+    /// Return the indices (and distances) of every entry within `radius`
+    /// of `sketch`.
+    pub fn query_radius(&self, sketch: &Sketch, radius: u32) -> Vec<(usize, u32)> {
+        let mut found = Vec::new();
+        if let Some(ref root) = self.root {
+            root.query_radius(sketch, radius, &mut found);
+        }
+        found
+    }
+}
+
+impl Node {
+    fn insert(&mut self, idx: usize, sketch: Sketch) {
+        let d = distance(&self.sketch, &sketch);
+        self.children
+            .entry(d)
+            .or_insert_with(|| {
+                Box::new(Node {
+                    idx: idx,
+                    sketch: sketch.clone(),
+                    children: HashMap::new(),
+                })
+            });
+        // If a child already occupied that exact distance bucket,
+        // descend into it instead of overwriting it.
+        if self.children[&d].idx != idx {
+            self.children.get_mut(&d).unwrap().insert(idx, sketch);
+        }
+    }
+
+    fn query_radius(&self, sketch: &Sketch, radius: u32, found: &mut Vec<(usize, u32)>) {
+        let d = distance(&self.sketch, sketch);
+        if d <= radius {
+            found.push((self.idx, d));
+        }
+        let lo = d.saturating_sub(radius);
+        let hi = d + radius;
+        for (&key, child) in &self.children {
+            if key >= lo && key <= hi {
+                child.query_radius(sketch, radius, found);
+            }
+        }
+    }
+}
+
+/// Union-find over `0..n`, used to merge files into near-duplicate
+/// clusters as radius queries turn up matches.
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        UnionFind { parent: (0..n).collect() }
+    }
+
+    fn find(&mut self, x: usize) -> usize {
+        if self.parent[x] != x {
+            self.parent[x] = self.find(self.parent[x]);
+        }
+        self.parent[x]
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (ra, rb) = (self.find(a), self.find(b));
+        if ra != rb {
+            self.parent[ra] = rb;
+        }
+    }
+}
+
+/// Shared by `find_similar`/`find_similar_cached`/`par_find_similar`:
+/// build the BK-tree over already-computed `sketches` (one per path, in
+/// the same order as `paths`) and union-find its way to clusters.
+fn cluster_sketches<P: AsRef<Path>>(paths: &[P],
+                                     threshold: u32,
+                                     sketches: Vec<Sketch>)
+                                     -> Vec<Vec<PathBuf>> {
+    let radius = 100u32.saturating_sub(threshold);
+    let mut tree = BKTree::new();
+    let mut uf = UnionFind::new(paths.len());
+    for (idx, sketch) in sketches.into_iter().enumerate() {
+        for (other_idx, _dist) in tree.query_radius(&sketch, radius) {
+            uf.union(idx, other_idx);
+        }
+        tree.insert(idx, sketch);
+    }
+
+    let mut clusters: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+    for (idx, path) in paths.iter().enumerate() {
+        let root = uf.find(idx);
+        clusters
+            .entry(root)
+            .or_insert_with(Vec::new)
+            .push(path.as_ref().to_path_buf());
+    }
+    clusters
+        .into_iter()
+        .map(|(_, v)| v)
+        .filter(|v| v.len() > 1)
+        .collect()
+}
+
+/// Cluster `paths` into groups of near-duplicates, where two files are
+/// considered near-duplicates if their estimated similarity is at least
+/// `threshold` percent. Returns only clusters with more than one member.
+pub fn find_similar<P: AsRef<Path>>(paths: &[P], threshold: u32) -> io::Result<Vec<Vec<PathBuf>>> {
+    let sketches = paths.iter()
+        .map(|p| Sketch::from_file(p, DEFAULT_SKETCH_SIZE, HashType::default()))
+        .collect::<io::Result<Vec<Sketch>>>()?;
+    Ok(cluster_sketches(paths, threshold, sketches))
+}
+
+/// Like `find_similar`, but looks up (and fills) each file's sketch in
+/// `cache` by path/size/mtime instead of always recomputing it from
+/// scratch, so repeated scans of an unchanged directory skip re-hashing
+/// every file.
+pub fn find_similar_cached<P: AsRef<Path>>(paths: &[P],
+                                            threshold: u32,
+                                            cache: &mut Cache)
+                                            -> io::Result<Vec<Vec<PathBuf>>> {
+    let sketches = paths.iter()
+        .map(|p| Sketch::from_file_cached(p, DEFAULT_SKETCH_SIZE, HashType::default(), cache))
+        .collect::<io::Result<Vec<Sketch>>>()?;
+    Ok(cluster_sketches(paths, threshold, sketches))
+}
+
+/// Like `find_similar`, but computes every file's sketch across a rayon
+/// thread pool before doing the (inherently sequential) BK-tree
+/// insertion and lookup. Only enabled with the `rayon` feature.
+#[cfg(feature = "rayon")]
+pub fn par_find_similar<P>(paths: &[P], threshold: u32) -> io::Result<Vec<Vec<PathBuf>>>
+    where P: AsRef<Path> + Sync
+{
+    use rayon::prelude::*;
+
+    let sketches: Vec<Sketch> = paths.par_iter()
+        .map(|p| Sketch::from_file(p, DEFAULT_SKETCH_SIZE, HashType::default()))
+        .collect::<io::Result<Vec<Sketch>>>()?;
+    Ok(cluster_sketches(paths, threshold, sketches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::Cursor;
+
+    fn sketch_of(lines: &[&str]) -> Sketch {
+        let text: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        // k == the union size of `a`'s and `b`'s lines below, so the
+        // merge-walk in `Sketch::estimate_similarity` sees both whole
+        // sketches and a-vs-b is the exact Jaccard ratio 4/6 rather than
+        // one diluted by a much larger k. A smaller union is still divided
+        // by k: `a` against itself comes out at 5/6.
+        Sketch::from_reader(Cursor::new(text.into_bytes()), 6, HashType::default()).unwrap()
+    }
+
+    /// Triangle-inequality pruning in `Node::query_radius` should never
+    /// change *which* entries are within radius of a query, only how
+    /// many nodes get visited while finding them.
+    #[test]
+    fn query_radius_matches_brute_force() {
+        let a = sketch_of(&["one", "two", "three", "four", "five"]);
+        let b = sketch_of(&["one", "two", "three", "four", "six"]);
+        let c = sketch_of(&["aaa", "bbb", "ccc", "ddd", "eee"]);
+        let sketches = vec![a.clone(), b.clone(), c.clone()];
+
+        let mut tree = BKTree::new();
+        for (idx, sketch) in sketches.iter().cloned().enumerate() {
+            tree.insert(idx, sketch);
+        }
+
+        let radius = 50;
+        let found: HashSet<usize> = tree.query_radius(&a, radius)
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect();
+        let brute_force: HashSet<usize> = sketches.iter()
+            .enumerate()
+            .filter(|&(_, s)| distance(&a, s) <= radius)
+            .map(|(idx, _)| idx)
+            .collect();
+
+        assert_eq!(found, brute_force);
+        assert!(found.contains(&0), "a query against itself must match");
+        assert!(found.contains(&1), "a near-duplicate (4 of 5 lines shared) must match");
+        assert!(!found.contains(&2), "a completely different file must not match");
+    }
+}
@@ -0,0 +1,246 @@
+/**
+ * On-disk cache of computed `SpanhashTop`s or `Sketch`es, keyed by
+ * path/size/mtime.
+ *
+ * Re-running similarity over the same files recomputes every line hash
+ * from scratch. This cache lets `SpanhashTop::from_file_cached` and
+ * `Sketch::from_file_cached` skip that work when a file's size and
+ * modification time haven't changed since it was last hashed.
+ */
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use diffcore::{HashType, SpanhashTop, DEFAULT_MAX_MEMORY};
+use sketch::Sketch;
+
+/// Identifies a cached entry by the file's canonical path, size, and
+/// modification time. If any of these no longer match what's on disk,
+/// the entry is stale.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheKey {
+    path: PathBuf,
+    len: u64,
+    mtime: SystemTime,
+}
+
+impl CacheKey {
+    fn for_path<P: AsRef<Path>>(p: P) -> io::Result<Self> {
+        let path = p.as_ref().canonicalize()?;
+        let meta = fs::metadata(&path)?;
+        Ok(CacheKey {
+            path: path,
+            len: meta.len(),
+            mtime: meta.modified()?,
+        })
+    }
+}
+
+/// A cache of computed sketches, persisted as a single file under a
+/// configurable directory. Used by both `SpanhashTop::from_file_cached`
+/// and `Sketch::from_file_cached`, so the same path/size/mtime key could
+/// in principle hold either type's bytes; each entry is tagged with the
+/// type it was serialized from, so a lookup for the wrong type is a
+/// miss (and gets recomputed) instead of deserializing into
+/// corrupted-but-valid-looking data.
+///
+/// Entries are kept as their already-serialized bytes rather than as
+/// live values: `SpanhashTop` doesn't implement `Clone` (a spilled
+/// `SpanhashTop` owns on-disk run files that must have exactly one
+/// owner), so a hit has to produce its own fresh, independently owned
+/// value by deserializing rather than cloning one. For the same reason a
+/// `SpanhashTop` that spilled to disk is never cached: its serialized
+/// form would only name run files that are deleted once it's dropped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    entries: Vec<(CacheKey, String, Vec<u8>)>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl Cache {
+    /// Load the cache file under `dir`, or start empty if it doesn't
+    /// exist yet or fails to parse.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Self {
+        let file = Self::cache_file(dir);
+        fs::read(&file)
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Persist the cache to `dir`'s cache file, if anything changed
+    /// since it was loaded.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let file = Self::cache_file(dir);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = bincode::serialize(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        fs::write(file, data)
+    }
+
+    fn cache_file<P: AsRef<Path>>(dir: P) -> PathBuf {
+        dir.as_ref().join("sketches.cache")
+    }
+
+    /// Deserialize a fresh, independently owned value from the cached
+    /// entry for `key`, if there is one, it's tagged as holding a `T`,
+    /// and it still parses. A key shared with a differently-typed entry
+    /// (e.g. a `SpanhashTop` cached where a `Sketch` is now being looked
+    /// up) is a miss, not an attempt to deserialize the wrong type.
+    fn get<T: DeserializeOwned>(&self, key: &CacheKey) -> Option<T> {
+        let type_tag = std::any::type_name::<T>();
+        self.entries
+            .iter()
+            .find(|&&(ref k, ref tag, _)| k == key && tag == type_tag)
+            .and_then(|&(_, _, ref bytes)| bincode::deserialize(bytes).ok())
+    }
+
+    fn insert<T: Serialize>(&mut self, key: CacheKey, value: &T) {
+        let bytes = match bincode::serialize(value) {
+            Ok(bytes) => bytes,
+            Err(_) => return,
+        };
+        let type_tag = std::any::type_name::<T>().to_string();
+        if let Some(existing) = self.entries.iter_mut().find(|&&mut (ref k, _, _)| *k == key) {
+            existing.1 = type_tag;
+            existing.2 = bytes;
+            self.dirty = true;
+            return;
+        }
+        self.entries.push((key, type_tag, bytes));
+        self.dirty = true;
+    }
+}
+
+impl SpanhashTop {
+    /// Like `from_file`, but consults `cache` first and only recomputes
+    /// the hash if the file's path/size/mtime aren't already cached.
+    /// Files of `DEFAULT_MAX_MEMORY` bytes or more spill to disk and are
+    /// recomputed on every call rather than cached.
+    pub fn from_file_cached<P: AsRef<Path>>(p: P,
+                                             is_binary: bool,
+                                             hash_type: HashType,
+                                             cache: &mut Cache)
+                                             -> io::Result<Self> {
+        let key = CacheKey::for_path(p.as_ref())?;
+        if let Some(cached) = cache.get(&key) {
+            return Ok(cached);
+        }
+        let computed = Self::from_file(p, is_binary, hash_type, DEFAULT_MAX_MEMORY)?;
+        // `from_reader` spills exactly when the hashed bytes reach the
+        // budget; a spilled value's run files don't outlive it.
+        if computed.len() < DEFAULT_MAX_MEMORY {
+            cache.insert(key, &computed);
+        }
+        Ok(computed)
+    }
+}
+
+impl Sketch {
+    /// Like `from_file`, but consults `cache` first and only recomputes
+    /// the sketch if the file's path/size/mtime aren't already cached.
+    pub fn from_file_cached<P: AsRef<Path>>(p: P,
+                                             k: usize,
+                                             hash_type: HashType,
+                                             cache: &mut Cache)
+                                             -> io::Result<Self> {
+        let key = CacheKey::for_path(p.as_ref())?;
+        if let Some(cached) = cache.get(&key) {
+            return Ok(cached);
+        }
+        let computed = Self::from_file(p, k, hash_type)?;
+        cache.insert(key, &computed);
+        Ok(computed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Removes the file at `path` when dropped, so a fixture cleans up
+    /// after itself even if an assertion panics partway through a test.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(name);
+            let mut f = fs::File::create(&path).unwrap();
+            f.write_all(b"hello").unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn get_misses_before_insert_and_hits_after() {
+        let file = TempFile::new("similarity-cache-test-roundtrip.txt");
+        let key = CacheKey::for_path(&file.0).unwrap();
+        let mut cache = Cache::default();
+        assert_eq!(cache.get::<String>(&key), None);
+        cache.insert(key.clone(), &"a sketch".to_string());
+        assert_eq!(cache.get::<String>(&key), Some("a sketch".to_string()));
+    }
+
+    #[test]
+    fn insert_overwrites_the_existing_entry_for_the_same_key() {
+        let file = TempFile::new("similarity-cache-test-overwrite.txt");
+        let key = CacheKey::for_path(&file.0).unwrap();
+        let mut cache = Cache::default();
+        cache.insert(key.clone(), &"first".to_string());
+        cache.insert(key.clone(), &"second".to_string());
+        assert_eq!(cache.get::<String>(&key), Some("second".to_string()));
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn get_is_a_miss_for_the_right_key_but_the_wrong_type() {
+        let file = TempFile::new("similarity-cache-test-type-tag.txt");
+        let key = CacheKey::for_path(&file.0).unwrap();
+        let mut cache = Cache::default();
+        cache.insert(key.clone(), &"a sketch".to_string());
+        assert_eq!(cache.get::<u32>(&key), None,
+                   "a lookup for a different type than what's stored under this key must miss, \
+                    not deserialize the wrong bytes");
+        assert_eq!(cache.get::<String>(&key), Some("a sketch".to_string()));
+    }
+
+    #[test]
+    fn save_is_a_no_op_until_something_is_inserted() {
+        let dir = std::env::temp_dir().join("similarity-cache-test-dir");
+        let _ = fs::remove_dir_all(&dir);
+
+        let cache = Cache::default();
+        assert!(!cache.dirty);
+        cache.save(&dir).unwrap();
+        assert!(!Cache::cache_file(&dir).exists(),
+                "save must not write anything while dirty is false");
+
+        let file = TempFile::new("similarity-cache-test-dirty-source.txt");
+        let key = CacheKey::for_path(&file.0).unwrap();
+        let mut cache = Cache::default();
+        cache.insert(key, &"a sketch".to_string());
+        assert!(cache.dirty);
+        cache.save(&dir).unwrap();
+        assert!(Cache::cache_file(&dir).exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
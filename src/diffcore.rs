@@ -4,18 +4,31 @@
  * Based on git's estimate_similarity function in diffcore-rename.c
  */
 use std::collections::hash_map::{HashMap, DefaultHasher};
-use std::cmp;
+use std::collections::BinaryHeap;
+use std::cmp::{self, Reverse};
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::hash::{Hash, Hasher};
-use std::io::{self, Read};
-use std::path::Path;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec;
 
+use bincode;
+use blake3;
+use crc32fast;
 use memchr;
+use twox_hash;
 
-const MAX_SCORE: f64 = 60000.0;
-const MIN_SCORE: f64 = 30000.0;
+use hll::HyperLogLog;
+
+pub(crate) const MAX_SCORE: f64 = 60000.0;
+pub(crate) const MIN_SCORE: f64 = 30000.0;
+
+/// Default in-memory budget, in bytes of raw line data, before
+/// `SpanhashTop::from_reader` starts spilling sorted runs to disk. Files
+/// smaller than this are hashed entirely in memory, same as before.
+pub const DEFAULT_MAX_MEMORY: usize = 64 * 1024 * 1024;
 
 use DiffResult;
 
@@ -45,6 +58,14 @@ pub fn estimate_similarity(left: SpanhashTop, right: SpanhashTop) -> DiffResult<
         (_, _) => (),
     }
 
+    // Cheap pre-filter: bound the Jaccard similarity of the two files'
+    // line sets from their HyperLogLog sketches before doing the full
+    // count_changes merge. If even the upper bound is already well below
+    // MIN_SCORE, the real answer isn't going to clear it either.
+    if left.hll.jaccard_upper_bound(&right.hll) * MAX_SCORE < MIN_SCORE {
+        return Ok(0.0);
+    }
+
     let (copied, _) = count_changes(left, right);
     Ok(copied as f64 * MAX_SCORE / max_size as f64)
 }
@@ -85,104 +106,416 @@ fn count_changes(left: SpanhashTop, right: SpanhashTop) -> (usize, usize) {
 }
 
 
-/// Hashing of a file
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
-pub struct SpanhashTop(HashMap<Vec<u8>, (u64, usize)>);
+/// Which hash function to use for per-line hashing.
+///
+/// `SipDefault` is `std`'s `DefaultHasher` (SipHash): always available,
+/// but slow and not guaranteed to be stable across Rust versions. The
+/// others are faster and/or stable, which matters once hashes get
+/// persisted (see the on-disk cache) or compared across machines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashType {
+    SipDefault,
+    Xxh3,
+    Blake3,
+    Crc32,
+}
+
+impl Default for HashType {
+    fn default() -> Self {
+        HashType::Xxh3
+    }
+}
+
+impl HashType {
+    pub fn hash(&self, data: &[u8]) -> u64 {
+        match *self {
+            HashType::SipDefault => {
+                let mut hasher = DefaultHasher::new();
+                data.hash(&mut hasher);
+                hasher.finish()
+            }
+            HashType::Xxh3 => twox_hash::xxh3::hash64(data),
+            HashType::Blake3 => {
+                let digest = blake3::hash(data);
+                let bytes = digest.as_bytes();
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(&bytes[..8]);
+                u64::from_le_bytes(buf)
+            }
+            HashType::Crc32 => crc32fast::hash(data) as u64,
+        }
+    }
+}
+
+/// How many lines `SpanhashTop`/`Sketch` buffer before hashing them as a
+/// batch. Bounds how much of the file a parallel hashing pass (see
+/// `hash_lines` below) has to hold in memory at once.
+pub(crate) const HASH_BATCH_LINES: usize = 4096;
+
+/// Hash a batch of lines, across a rayon thread pool when the `rayon`
+/// feature is enabled, in order otherwise. Both `SpanhashTop::from_reader`
+/// and `Sketch::from_reader` stream lines into batches of this size so
+/// the (otherwise sequential) per-line hashing can run in parallel.
+#[cfg(feature = "rayon")]
+pub(crate) fn hash_lines(lines: &[Vec<u8>], hash_type: HashType) -> Vec<u64> {
+    use rayon::prelude::*;
+    lines.par_iter().map(|line| hash_type.hash(line)).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) fn hash_lines(lines: &[Vec<u8>], hash_type: HashType) -> Vec<u64> {
+    lines.iter().map(|line| hash_type.hash(line)).collect()
+}
+
+/// Split `reader` into lines the way `SpanhashTop` always has: CRLF
+/// normalized to LF (unless `is_binary`), and a line longer than
+/// `max_line_length` bytes split into pieces of at most that length. `Sketch`
+/// uses this too, so the two similarity paths agree on what a "line" is.
+pub(crate) fn for_each_line<R, F>(mut reader: R,
+                                   is_binary: bool,
+                                   max_line_length: usize,
+                                   mut f: F)
+                                   -> io::Result<()>
+    where R: Read,
+          F: FnMut(Vec<u8>) -> io::Result<()>
+{
+    let mut buf: Vec<u8> = vec![0; max_line_length];
+    let mut is_done = false;
+    let mut buf_len = 0;
+    while !is_done {
+        buf.resize(max_line_length, 0);
+        match reader.read(&mut buf[buf_len..max_line_length]) {
+            Ok(0) => {
+                is_done = true;
+            }
+            Ok(n) => {
+                buf_len += n;
+                if buf_len < max_line_length {
+                    continue;
+                }
+            }
+            Err(_) => {
+                is_done = true;
+            }
+        }
+        while buf_len > 0 {
+            let end_idx = if let Some(idx) = memchr::memchr(b'\n', &buf[..buf_len]) {
+                idx + 1
+            } else if buf_len < max_line_length {
+                break;
+            } else {
+                max_line_length
+            };
+            let rest = buf.split_off(end_idx);
+            buf_len = buf_len - end_idx;
+            let has_crlf = end_idx > 1 && buf[end_idx - 1] == b'\n' &&
+                           buf[end_idx - 2] == b'\r';
+            if !is_binary && has_crlf {
+                // Ignore CR in CRLF sequence if text
+                buf[end_idx - 2] = b'\n';
+                buf.pop();
+            }
+            f(buf)?;
+            buf = rest;
+        }
+    }
+    Ok(())
+}
+
+/// A set of sorted-run files spilled to disk by `SpanhashTop::from_reader`.
+/// The files are temporary and are removed as soon as this is dropped.
+/// `SpanhashIter`/`MergeIter` hold onto the `RunPaths` that produced
+/// them for exactly this reason: the files must stay alive (and
+/// eventually get cleaned up) for as long as anything might still be
+/// reading them, not just until `into_iter()` returns.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunPaths(Vec<PathBuf>);
+
+impl Drop for RunPaths {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn new_run_path() -> PathBuf {
+    let n = RUN_COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("similarity-run-{}-{}.bin", std::process::id(), n))
+}
+
+/// Sort `entries` by `hashval` and write them out as a single run: a
+/// flat sequence of bincode-framed `Spanhash` records.
+fn spill_run(entries: Vec<Spanhash>) -> io::Result<PathBuf> {
+    let mut entries = entries;
+    entries.sort_by(|a, b| a.hashval.cmp(&b.hashval));
+    let path = new_run_path();
+    let mut w = BufWriter::new(File::create(&path)?);
+    for entry in &entries {
+        bincode::serialize_into(&mut w, entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    }
+    Ok(path)
+}
+
+/// Hash a batch of lines (in parallel when the `rayon` feature is on)
+/// and fold the results into `h`/`hll`/`size`/`h_bytes`. Pulled out of
+/// `SpanhashTop::from_reader` so the same logic can flush a full batch
+/// mid-stream and whatever's left over once the reader is exhausted.
+fn absorb_batch(batch: Vec<Vec<u8>>,
+                 hash_type: HashType,
+                 h: &mut HashMap<Vec<u8>, (u64, usize)>,
+                 hll: &mut HyperLogLog,
+                 size: &mut usize,
+                 h_bytes: &mut usize) {
+    let hashes = hash_lines(&batch, hash_type);
+    for (line, hashed) in batch.into_iter().zip(hashes) {
+        hll.add(hashed);
+        let cnt = line.len();
+        *size += cnt;
+        *h_bytes += cnt;
+        let e = h.entry(line).or_insert((hashed, 0));
+        e.1 += cnt;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Source {
+    /// Small enough to keep every distinct line in memory.
+    Memory(HashMap<Vec<u8>, (u64, usize)>),
+    /// Too large for `DEFAULT_MAX_MEMORY`-ish budgets: hashed in bounded
+    /// chunks, each spilled to disk as its own sorted run.
+    Spilled(RunPaths),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::Memory(HashMap::new())
+    }
+}
+
+/// Hashing of a file.
+///
+/// Normal-sized files are hashed entirely into an in-memory
+/// `HashMap<line, (hashval, occurrences)>`. Files bigger than the
+/// `max_memory` budget passed to `from_reader` are instead hashed in
+/// bounded-size chunks that get spilled to disk as sorted runs; those
+/// runs are merged back into ascending-`hashval` order lazily, so
+/// `count_changes` never has to hold the full file in memory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SpanhashTop {
+    size: usize,
+    source: Source,
+    hll: HyperLogLog,
+}
 
 impl SpanhashTop {
-    pub fn from_file<P: AsRef<Path>>(p: P, is_binary: bool) -> io::Result<Self> {
+    pub fn from_file<P: AsRef<Path>>(p: P,
+                                      is_binary: bool,
+                                      hash_type: HashType,
+                                      max_memory: usize)
+                                      -> io::Result<Self> {
         let f = File::open(p.as_ref())?;
-        Self::from_reader(f, is_binary)
+        Self::from_reader(f, is_binary, hash_type, max_memory)
     }
-    pub fn from_reader<R: Read>(mut reader: R, is_binary: bool) -> io::Result<Self> {
+    pub fn from_reader<R: Read>(reader: R,
+                                 is_binary: bool,
+                                 hash_type: HashType,
+                                 max_memory: usize)
+                                 -> io::Result<Self> {
         let max_line_length = 64;
-        let mut h = HashMap::new();
-        let mut buf: Vec<u8> = vec![0; 128];
-        let mut is_done = false;
-        let mut buf_len = 0;
-        while !is_done {
-            buf.resize(max_line_length, 0);
-            match reader.read(&mut buf[buf_len..max_line_length]) {
-                Ok(0) => {
-                    is_done = true;
-                }
-                Ok(n) => {
-                    buf_len += n;
-                    if buf_len < max_line_length {
-                        continue;
-                    }
-                }
-                Err(_) => {
-                    is_done = true;
-                    ()
-                }
+        let mut h: HashMap<Vec<u8>, (u64, usize)> = HashMap::new();
+        let mut h_bytes = 0;
+        let mut runs = Vec::new();
+        let mut size = 0;
+        let mut hll = HyperLogLog::new();
+        let mut batch: Vec<Vec<u8>> = Vec::with_capacity(HASH_BATCH_LINES);
+        for_each_line(reader, is_binary, max_line_length, |line| {
+            batch.push(line);
+            if batch.len() < HASH_BATCH_LINES {
+                return Ok(());
             }
-            while buf_len > 0 {
-                let end_idx = if let Some(idx) = memchr::memchr(b'\n', &buf[..buf_len]) {
-                    idx + 1
-                } else if buf_len < max_line_length {
-                    break;
-                } else {
-                    max_line_length
-                };
-                let rest = buf.split_off(end_idx);
-                buf_len = buf_len - end_idx;
-                let has_crlf = end_idx > 1 && buf[end_idx - 1] == b'\n' &&
-                               buf[end_idx - 2] == b'\r';
-                if !is_binary && has_crlf {
-                    // Ignore CR in CRLF sequence if text
-                    buf[end_idx - 2] = b'\n';
-                    buf.pop();
-                }
-                let hashed = {
-                    let mut hasher = DefaultHasher::new();
-                    buf.hash(&mut hasher);
-                    hasher.finish()
-                };
-                let cnt = buf.len();
-                let mut e = h.entry(buf).or_insert((hashed, 0));
-                e.1 += cnt;
-                buf = rest;
+            let flushed = std::mem::replace(&mut batch, Vec::with_capacity(HASH_BATCH_LINES));
+            absorb_batch(flushed, hash_type, &mut h, &mut hll, &mut size, &mut h_bytes);
+            if h_bytes >= max_memory {
+                let entries = h.drain()
+                    .map(|(data, (hashed, occ))| {
+                        Spanhash {
+                            data: data,
+                            hashval: hashed,
+                            occurrences: occ,
+                        }
+                    })
+                    .collect();
+                runs.push(spill_run(entries)?);
+                h_bytes = 0;
+            }
+            Ok(())
+        })?;
+        if !batch.is_empty() {
+            absorb_batch(batch, hash_type, &mut h, &mut hll, &mut size, &mut h_bytes);
+            if h_bytes >= max_memory {
+                let entries = h.drain()
+                    .map(|(data, (hashed, occ))| {
+                        Spanhash {
+                            data: data,
+                            hashval: hashed,
+                            occurrences: occ,
+                        }
+                    })
+                    .collect();
+                runs.push(spill_run(entries)?);
             }
         }
-        Ok(SpanhashTop(h))
+        if runs.is_empty() {
+            Ok(SpanhashTop { size: size, source: Source::Memory(h), hll: hll })
+        } else {
+            if !h.is_empty() {
+                let entries = h.into_iter()
+                    .map(|(data, (hashed, occ))| {
+                        Spanhash {
+                            data: data,
+                            hashval: hashed,
+                            occurrences: occ,
+                        }
+                    })
+                    .collect();
+                runs.push(spill_run(entries)?);
+            }
+            Ok(SpanhashTop {
+                size: size,
+                source: Source::Spilled(RunPaths(runs)),
+                hll: hll,
+            })
+        }
     }
     pub fn len(&self) -> usize {
-        self.0.values().fold(0, |a, &(_, occ)| a + occ)
+        self.size
+    }
+}
+
+/// Reads one sorted run's `Spanhash` records back, one at a time.
+struct RunReader(BufReader<File>);
+
+impl RunReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(RunReader(BufReader::new(File::open(path)?)))
+    }
+    fn next(&mut self) -> Option<Spanhash> {
+        bincode::deserialize_from(&mut self.0).ok()
+    }
+}
+
+/// Lazily merges however many sorted runs were spilled, in ascending
+/// `hashval` order, keeping only one pending record per run in memory at
+/// a time. Records with equal `hashval` that came from different runs
+/// (the same line straddling a spill boundary) are coalesced into a
+/// single record with summed `occurrences` as they're popped, so
+/// `count_changes` never sees more than one record per `hashval` per
+/// side here, same as the in-memory path.
+pub struct MergeIter {
+    readers: Vec<RunReader>,
+    heads: Vec<Option<Spanhash>>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    // Keeps the backing run files alive (and, via `RunPaths`'s `Drop`,
+    // cleans them up) for as long as `readers` might still be reading
+    // them, i.e. until this iterator itself is dropped.
+    _runs: RunPaths,
+}
+
+impl Iterator for MergeIter {
+    type Item = Spanhash;
+    fn next(&mut self) -> Option<Spanhash> {
+        let Reverse((_, idx)) = self.heap.pop()?;
+        let mut item = self.heads[idx].take().unwrap();
+        if let Some(next_item) = self.readers[idx].next() {
+            self.heap.push(Reverse((next_item.hashval, idx)));
+            self.heads[idx] = Some(next_item);
+        }
+        // Coalesce any other runs' records that share this hashval (the
+        // same line, split across a spill boundary) into `item`, rather
+        // than handing count_changes two records for one hashval.
+        while let Some(&Reverse((hashval, _))) = self.heap.peek() {
+            if hashval != item.hashval {
+                break;
+            }
+            let Reverse((_, idx)) = self.heap.pop().unwrap();
+            let next = self.heads[idx].take().unwrap();
+            item.occurrences += next.occurrences;
+            if let Some(next_item) = self.readers[idx].next() {
+                self.heap.push(Reverse((next_item.hashval, idx)));
+                self.heads[idx] = Some(next_item);
+            }
+        }
+        Some(item)
+    }
+}
+
+/// Either a plain sorted `Vec` (small files) or a lazy disk-run merge
+/// (files that spilled); both yield `Spanhash` in ascending `hashval`
+/// order, which is all `count_changes` needs.
+pub enum SpanhashIter {
+    Memory(vec::IntoIter<Spanhash>),
+    Merged(MergeIter),
+}
+
+impl Iterator for SpanhashIter {
+    type Item = Spanhash;
+    fn next(&mut self) -> Option<Spanhash> {
+        match *self {
+            SpanhashIter::Memory(ref mut it) => it.next(),
+            SpanhashIter::Merged(ref mut it) => it.next(),
+        }
     }
 }
 
 impl IntoIterator for SpanhashTop {
-    type IntoIter = vec::IntoIter<Spanhash>;
+    type IntoIter = SpanhashIter;
     type Item = Spanhash;
     fn into_iter(self) -> Self::IntoIter {
-        let mut v: Vec<Self::Item> = self.0
-            .into_iter()
-            .map(|(data, (hashed, occ))| {
-                Spanhash {
-                    data: data,
-                    hashval: hashed,
-                    occurrences: occ,
+        match self.source {
+            Source::Memory(h) => {
+                let mut v: Vec<Spanhash> = h.into_iter()
+                    .map(|(data, (hashed, occ))| {
+                        Spanhash {
+                            data: data,
+                            hashval: hashed,
+                            occurrences: occ,
+                        }
+                    })
+                    .collect();
+                v.sort_by(|left, right| left.hashval.cmp(&right.hashval));
+                SpanhashIter::Memory(v.into_iter())
+            }
+            Source::Spilled(run_paths) => {
+                let mut readers: Vec<RunReader> = run_paths.0
+                    .iter()
+                    .map(|p| RunReader::open(p))
+                    .collect::<io::Result<_>>()
+                    .expect("failed to reopen spilled run");
+                let mut heads = Vec::with_capacity(readers.len());
+                let mut heap = BinaryHeap::new();
+                for (idx, reader) in readers.iter_mut().enumerate() {
+                    let head = reader.next();
+                    if let Some(ref item) = head {
+                        heap.push(Reverse((item.hashval, idx)));
+                    }
+                    heads.push(head);
                 }
-            })
-            .collect();
-        v.sort_by(|left, right| {
-            // We want to sort occurrence from largest to smallest.
-            // Our second sort key will be the hash value, which
-            // we'll sort from smallest to largest.
-            match (left.occurrences, right.occurrences) {
-                (0, 0) => return cmp::Ordering::Equal,
-                (0, _) => return cmp::Ordering::Greater,
-                (_, 0) => return cmp::Ordering::Less,
-                (_, _) => (),
+                SpanhashIter::Merged(MergeIter {
+                    readers: readers,
+                    heads: heads,
+                    heap: heap,
+                    _runs: run_paths,
+                })
             }
-            left.hashval.cmp(&right.hashval)
-        });
-        v.into_iter()
+        }
     }
 }
 
-#[derive(Clone, Default, PartialEq, Eq)]
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Spanhash {
     data: Vec<u8>,
     hashval: u64,
@@ -199,3 +532,84 @@ impl fmt::Debug for Spanhash {
                o = self.occurrences)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const TEXT: &'static str = "apple\nbanana\ncarrot\ndate\neggplant\nfig\ngrape\n";
+
+    fn run_files_in_temp_dir() -> usize {
+        fs::read_dir(std::env::temp_dir())
+            .map(|entries| {
+                entries.filter_map(|e| e.ok())
+                    .filter(|e| e.file_name().to_string_lossy().starts_with("similarity-run-"))
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn spilling_preserves_len_and_ascending_merge_order() {
+        let in_memory = SpanhashTop::from_reader(Cursor::new(TEXT.as_bytes()),
+                                                  false,
+                                                  HashType::default(),
+                                                  DEFAULT_MAX_MEMORY)
+            .unwrap();
+        // A tiny budget forces the hashed lines to be spilled to disk
+        // (the budget is checked once per HASH_BATCH_LINES batch).
+        let spilled = SpanhashTop::from_reader(Cursor::new(TEXT.as_bytes()),
+                                                false,
+                                                HashType::default(),
+                                                1)
+            .unwrap();
+        assert_eq!(in_memory.len(), spilled.len());
+
+        let merged: Vec<Spanhash> = spilled.into_iter().collect();
+        let hashvals: Vec<u64> = merged.iter().map(|s| s.hashval).collect();
+        let mut sorted = hashvals.clone();
+        sorted.sort();
+        assert_eq!(hashvals, sorted, "merged runs must come out in ascending hashval order");
+    }
+
+    #[test]
+    fn coalesces_duplicate_hashvals_split_across_a_spill_boundary() {
+        // A tiny budget spills once per full HASH_BATCH_LINES batch, so two
+        // batches' worth of lines with "carrot" on every other line put
+        // "carrot" into two separate runs.
+        let text: String = (0..2 * HASH_BATCH_LINES)
+            .map(|i| if i % 2 == 0 { "carrot\n".to_string() } else { format!("line{}\n", i) })
+            .collect();
+        let spilled = SpanhashTop::from_reader(Cursor::new(text.as_bytes()),
+                                                false,
+                                                HashType::default(),
+                                                1)
+            .unwrap();
+        let merged: Vec<Spanhash> = spilled.into_iter().collect();
+        let mut hashvals: Vec<u64> = merged.iter().map(|s| s.hashval).collect();
+        let before_dedup = hashvals.len();
+        hashvals.sort();
+        hashvals.dedup();
+        assert_eq!(hashvals.len(), before_dedup, "merge must coalesce equal hashvals from different runs");
+
+        let carrot_hash = HashType::default().hash(b"carrot\n");
+        let carrot = merged.iter().find(|s| s.hashval == carrot_hash).unwrap();
+        assert_eq!(carrot.occurrences, "carrot\n".len() * HASH_BATCH_LINES);
+    }
+
+    #[test]
+    fn spilled_run_files_are_removed_once_the_iterator_is_dropped() {
+        let before = run_files_in_temp_dir();
+        let spilled = SpanhashTop::from_reader(Cursor::new(TEXT.as_bytes()),
+                                                false,
+                                                HashType::default(),
+                                                1)
+            .unwrap();
+        let iter = spilled.into_iter();
+        // Run files must still be around while something might read them.
+        assert!(run_files_in_temp_dir() > before);
+        drop(iter);
+        assert_eq!(run_files_in_temp_dir(), before);
+    }
+}
@@ -0,0 +1,99 @@
+/**
+ * HyperLogLog cardinality estimation.
+ *
+ * Used as a cheap pre-filter in `estimate_similarity`: before doing the
+ * full `count_changes` merge, estimate each file's distinct-line
+ * cardinality and their union's cardinality from a small fixed register
+ * array, and reject obviously-dissimilar pairs without ever reading
+ * their full `SpanhashTop`.
+ */
+use std::cmp;
+
+const NUM_REGISTERS_LOG2: u32 = 10;
+const NUM_REGISTERS: usize = 1 << NUM_REGISTERS_LOG2;
+
+/// A fixed-size HyperLogLog sketch of a stream of `u64` hashes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog { registers: vec![0; NUM_REGISTERS] }
+    }
+}
+
+impl HyperLogLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record one more hash into the sketch.
+    pub fn add(&mut self, hash: u64) {
+        let idx = (hash & (NUM_REGISTERS as u64 - 1)) as usize;
+        let rest = hash >> NUM_REGISTERS_LOG2;
+        // `rest` only has 64 - NUM_REGISTERS_LOG2 significant bits (the
+        // rest were shifted away and read as leading zeros), so correct
+        // for that when taking the leading-zero run as the register's
+        // rank.
+        let rank = (rest.leading_zeros() - NUM_REGISTERS_LOG2 + 1) as u8;
+        self.registers[idx] = cmp::max(self.registers[idx], rank);
+    }
+
+    /// Fold another sketch's registers into this one (register-wise max),
+    /// producing the sketch of the union of both inputs' hashes.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (a, b) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *a = cmp::max(*a, *b);
+        }
+    }
+
+    /// Estimate the number of distinct hashes added so far.
+    pub fn estimate(&self) -> f64 {
+        let m = NUM_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // Small-range correction (linear counting).
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+
+    /// Relative standard error of a single cardinality estimate from
+    /// this sketch's register count (the standard HyperLogLog
+    /// `1.04/sqrt(m)` bound).
+    fn relative_error() -> f64 {
+        1.04 / (NUM_REGISTERS as f64).sqrt()
+    }
+
+    /// Upper bound on the Jaccard similarity of the hash sets behind
+    /// `self` and `other`, from their cardinalities and their union's
+    /// cardinality (`|A| + |B| - |A union B| = |A intersect B|`), padded
+    /// with a few standard errors of headroom.
+    ///
+    /// The padding matters: `intersection` subtracts three
+    /// independently-noisy cardinality estimates, so its error can be
+    /// far larger than any single estimate's ~3.3% relative error
+    /// (cancellation). A caller that wants to safely reject a pair as
+    /// dissimilar needs an upper bound, not a point estimate - a point
+    /// estimate can land a genuinely similar pair below the reject
+    /// threshold purely on HLL noise.
+    pub fn jaccard_upper_bound(&self, other: &HyperLogLog) -> f64 {
+        let card_a = self.estimate();
+        let card_b = other.estimate();
+        let mut union = self.clone();
+        union.merge(other);
+        let card_union = union.estimate();
+        if card_union <= 0.0 {
+            return 1.0;
+        }
+        let margin = 3.0 * Self::relative_error() * (card_a + card_b + card_union);
+        let intersection = (card_a + card_b - card_union + margin).max(0.0);
+        (intersection / card_union).min(1.0)
+    }
+}
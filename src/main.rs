@@ -2,7 +2,16 @@
 extern crate clap;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate blake3;
+extern crate crc32fast;
 extern crate memchr;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+extern crate serde;
+extern crate twox_hash;
 
 use std::collections::hash_map::HashMap;
 use std::collections::HashSet;
@@ -21,11 +30,19 @@ pub use errors::Error as DiffError;
 pub use errors::ResultExt;
 
 pub mod diffcore;
+pub mod sketch;
+pub mod bktree;
+pub mod cache;
+pub mod hll;
 
 pub fn run<P1, P2>(left: P1, right: P2, _is_binary: bool) -> DiffResult<f64>
     where P1: AsRef<Path>,
           P2: AsRef<Path>
 {
+    #[cfg(feature = "rayon")]
+    let (left, run_len): (HashMap<u32, Vec<(usize, f64)>>, _) =
+        par_trigramize_file_to_table(left.as_ref())?;
+    #[cfg(not(feature = "rayon"))]
     let (left, run_len): (HashMap<u32, Vec<(usize, f64)>>, _) =
         trigramize_file_to_table(left.as_ref())?;
     let right: Vec<HashSet<u32>> = trigramize_file(right.as_ref())?;
@@ -127,6 +144,38 @@ fn trigramize_file_to_table<P>(filename: P) -> DiffResult<(HashMap<u32, Vec<(usi
     Ok((h, last_index))
 }
 
+/// Same as `trigramize_file_to_table`, but hashes lines across a rayon
+/// thread pool instead of one at a time. Only enabled with the `rayon`
+/// feature; the sequential version above is always available.
+#[cfg(feature = "rayon")]
+fn par_trigramize_file_to_table<P>(filename: P)
+    -> DiffResult<(HashMap<u32, Vec<(usize, f64)>>, usize)>
+    where P: AsRef<Path>
+{
+    use rayon::prelude::*;
+    let f = File::open(filename.as_ref())
+        .chain_err(|| format!("failed to open file {}", filename.as_ref().display()))?;
+    let mut lines = Vec::new();
+    for line in BufReader::new(f).lines() {
+        lines.push(line.chain_err(|| "failed to get line from reader")?);
+    }
+    let last_index = lines.len();
+    let per_line: Vec<(usize, HashSet<u32>)> = lines.into_par_iter()
+        .enumerate()
+        .map(|(x, line)| (x + 1, make_trigrams(&format!("{}\n", line))))
+        .collect();
+    let mut h = HashMap::new();
+    for (idx, trigram_line) in per_line {
+        let tri_len = trigram_line.len();
+        for tri in trigram_line {
+            h.entry(tri)
+                .or_insert(Vec::new())
+                .push((idx, 1.0 / tri_len as f64));
+        }
+    }
+    Ok((h, last_index))
+}
+
 fn trigramize_file<P>(filename: P) -> DiffResult<Vec<HashSet<u32>>>
     where P: AsRef<Path>
 {
@@ -168,6 +217,56 @@ fn make_trigrams(text: &str) -> HashSet<u32> {
 }
 
 
+/// Cluster near-duplicate files under `dir` and print each cluster found.
+/// If `cache_dir` is given, each file's sketch is looked up (and saved)
+/// there by path/size/mtime, so an unchanged directory doesn't get
+/// rehashed on every run.
+fn run_dir<P: AsRef<Path>>(dir: P, threshold: u32, cache_dir: Option<&str>) {
+    let entries = match std::fs::read_dir(dir.as_ref()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("error: failed to read directory {}: {}", dir.as_ref().display(), e);
+            std::process::exit(1);
+        }
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    let result = if let Some(cache_dir) = cache_dir {
+        let mut sketch_cache = cache::Cache::load(cache_dir);
+        let result = bktree::find_similar_cached(&paths, threshold, &mut sketch_cache);
+        if let Err(e) = sketch_cache.save(cache_dir) {
+            eprintln!("warning: failed to save sketch cache: {}", e);
+        }
+        result
+    } else {
+        #[cfg(feature = "rayon")]
+        { bktree::par_find_similar(&paths, threshold) }
+        #[cfg(not(feature = "rayon"))]
+        { bktree::find_similar(&paths, threshold) }
+    };
+    let clusters = match result {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            eprintln!("error: failed to cluster files: {}", e);
+            std::process::exit(1);
+        }
+    };
+    for (idx, cluster) in clusters.iter().enumerate() {
+        println!("cluster {}:", idx);
+        for path in cluster {
+            println!("  {}", path.display());
+        }
+    }
+}
+
 pub fn main() {
     let matches = App::new("similarity")
         .version(crate_version!())
@@ -182,7 +281,32 @@ pub fn main() {
         .arg(Arg::with_name("binary")
             .long("binary")
             .help("treat files as binary files (don't ignore CRLF)"))
+        .arg(Arg::with_name("dir")
+            .long("dir")
+            .takes_value(true)
+            .help("cluster near-duplicate files under this directory instead of \
+                    comparing two files"))
+        .arg(Arg::with_name("threshold")
+            .long("threshold")
+            .takes_value(true)
+            .requires("dir")
+            .help("minimum similarity percent (0-100) for --dir clustering \
+                    (default 80)"))
+        .arg(Arg::with_name("cache-dir")
+            .long("cache-dir")
+            .takes_value(true)
+            .requires("dir")
+            .help("persist computed sketches under this directory across \
+                    --dir runs, skipping unchanged files"))
         .get_matches();
+    if let Some(dir) = matches.value_of("dir") {
+        let threshold = matches.value_of("threshold")
+            .map(|t| t.parse().expect("--threshold must be an integer 0-100"))
+            .unwrap_or(80);
+        let cache_dir = matches.value_of("cache-dir");
+        run_dir(dir, threshold, cache_dir);
+        return;
+    }
     let left = matches.value_of("left").unwrap();
     let right = matches.value_of("right").unwrap();
     let is_binary = matches.is_present("binary");
@@ -0,0 +1,165 @@
+/**
+ * MinHash-style "bottom-k" sketches for fast, memory-bounded similarity.
+ *
+ * Unlike `SpanhashTop`, which keeps every distinct line in a `HashMap`, a
+ * `Sketch` keeps only the `k` smallest distinct line hashes it has seen.
+ * That's enough to estimate the Jaccard similarity of two files using
+ * only `k` `u64`s per file, regardless of how large the file is.
+ */
+use std::cmp;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use diffcore::{self, HashType, MAX_SCORE, MIN_SCORE, HASH_BATCH_LINES};
+use hll::HyperLogLog;
+
+/// Default number of hashes kept per sketch when none is specified.
+pub const DEFAULT_SKETCH_SIZE: usize = 128;
+
+/// A fixed-size "bottom-k" MinHash sketch of a file's lines.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sketch {
+    k: usize,
+    hashes: Vec<u64>,
+    hll: HyperLogLog,
+}
+
+impl Sketch {
+    pub fn from_file<P: AsRef<Path>>(p: P, k: usize, hash_type: HashType) -> io::Result<Self> {
+        let f = File::open(p.as_ref())?;
+        Self::from_reader(f, k, hash_type)
+    }
+
+    /// Reuses `SpanhashTop`'s own line splitting (CRLF normalization,
+    /// max line length) so the two similarity paths agree on what a
+    /// "line" is, and hashes through the same pluggable `HashType` it
+    /// uses rather than a hardcoded hasher. Lines are hashed in batches
+    /// via `diffcore::hash_lines`, which runs across a rayon thread pool
+    /// when the `rayon` feature is enabled.
+    pub fn from_reader<R: Read>(reader: R, k: usize, hash_type: HashType) -> io::Result<Self> {
+        // Keep the k smallest distinct hashes seen so far in a sorted
+        // set, evicting the current largest whenever we overflow k.
+        let mut smallest: BTreeSet<u64> = BTreeSet::new();
+        let mut hll = HyperLogLog::new();
+        let mut batch: Vec<Vec<u8>> = Vec::with_capacity(HASH_BATCH_LINES);
+        diffcore::for_each_line(reader, false, 64, |line| {
+            batch.push(line);
+            if batch.len() >= HASH_BATCH_LINES {
+                let flushed = std::mem::replace(&mut batch, Vec::with_capacity(HASH_BATCH_LINES));
+                Self::absorb_batch(flushed, hash_type, &mut smallest, &mut hll, k);
+            }
+            Ok(())
+        })?;
+        if !batch.is_empty() {
+            Self::absorb_batch(batch, hash_type, &mut smallest, &mut hll, k);
+        }
+        Ok(Sketch {
+            k: k,
+            hashes: smallest.into_iter().collect(),
+            hll: hll,
+        })
+    }
+
+    /// Hash a batch of lines (in parallel when the `rayon` feature is
+    /// on) and fold the results into `smallest`/`hll`.
+    fn absorb_batch(batch: Vec<Vec<u8>>,
+                     hash_type: HashType,
+                     smallest: &mut BTreeSet<u64>,
+                     hll: &mut HyperLogLog,
+                     k: usize) {
+        for hashval in diffcore::hash_lines(&batch, hash_type) {
+            hll.add(hashval);
+            smallest.insert(hashval);
+            if smallest.len() > k {
+                let largest = *smallest.iter().next_back().unwrap();
+                smallest.remove(&largest);
+            }
+        }
+    }
+
+    /// Number of hashes actually held (fewer than `k` for small files).
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Estimate the Jaccard similarity of `self` and `other`, scaled to
+    /// the crate's 0..MAX_SCORE range (see
+    /// `diffcore::estimate_similarity`). The shared count is always
+    /// divided by `k`, so when the two sketches together hold fewer than
+    /// `k` distinct hashes the result is below the exact ratio (two
+    /// identical 3-line files with `k` = 128 score 3/128 of `MAX_SCORE`).
+    pub fn estimate_similarity(&self, other: &Sketch) -> f64 {
+        let k = cmp::min(self.k, other.k);
+        if k == 0 {
+            return MAX_SCORE;
+        }
+        // Cheap pre-filter: bound the Jaccard similarity from the
+        // HyperLogLog sketches before doing the full merge-walk below. If
+        // even the upper bound is already well below MIN_SCORE, the real
+        // answer isn't going to clear it either (same trick as
+        // diffcore::estimate_similarity).
+        if self.hll.jaccard_upper_bound(&other.hll) * MAX_SCORE < MIN_SCORE {
+            return 0.0;
+        }
+        // Merge-walk both sorted hash lists, taking the k smallest
+        // hashes of the union and counting how many of those appear in
+        // both sketches.
+        let (mut i, mut j, mut taken, mut both) = (0, 0, 0, 0);
+        while taken < k && (i < self.hashes.len() || j < other.hashes.len()) {
+            match (self.hashes.get(i), other.hashes.get(j)) {
+                (Some(&a), Some(&b)) if a == b => {
+                    both += 1;
+                    i += 1;
+                    j += 1;
+                }
+                (Some(&a), Some(&b)) if a < b => i += 1,
+                (Some(_), Some(_)) => j += 1,
+                (Some(_), None) => i += 1,
+                (None, Some(_)) => j += 1,
+                (None, None) => unreachable!(),
+            }
+            taken += 1;
+        }
+        both as f64 * MAX_SCORE / k as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sketch_of(lines: &[&str], k: usize) -> Sketch {
+        let text: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        Sketch::from_reader(Cursor::new(text.into_bytes()), k, HashType::default()).unwrap()
+    }
+
+    #[test]
+    fn identical_files_estimate_max_score() {
+        // k == the distinct line count, so the merge-walk consumes
+        // every hash from both sides instead of stopping early and
+        // diluting the ratio by a much larger k.
+        let a = sketch_of(&["one", "two", "three"], 3);
+        let b = sketch_of(&["one", "two", "three"], 3);
+        assert_eq!(a.estimate_similarity(&b), MAX_SCORE);
+    }
+
+    #[test]
+    fn disjoint_files_estimate_zero() {
+        let a = sketch_of(&["one", "two", "three", "four", "five"], 5);
+        let b = sketch_of(&["aaa", "bbb", "ccc", "ddd", "eee"], 5);
+        assert_eq!(a.estimate_similarity(&b), 0.0);
+    }
+
+    #[test]
+    fn partial_overlap_estimates_the_exact_jaccard_ratio() {
+        // k == the union size of the two files' lines, so the result is
+        // an exact Jaccard ratio rather than one diluted by a much
+        // larger k (same trick bktree's tests use).
+        let a = sketch_of(&["one", "two", "three", "four", "five"], 6);
+        let b = sketch_of(&["one", "two", "three", "four", "six"], 6);
+        assert_eq!(a.estimate_similarity(&b), 4.0 * MAX_SCORE / 6.0);
+    }
+}